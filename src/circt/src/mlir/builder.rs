@@ -3,6 +3,29 @@
 //! A builder for IR operations.
 
 use crate::crate_prelude::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+
+/// A listener notified of IR mutations performed through a [`Builder`].
+///
+/// Modeled on MLIR's `OpBuilder::Listener`: register one via
+/// [`Builder::set_listener`] to, for example, push freshly created
+/// operations onto a worklist for re-legalization.
+pub trait BuilderListener {
+    /// Called right after an operation has been inserted into a block.
+    fn notify_operation_inserted(&mut self, op: MlirOperation);
+
+    /// Called right after a block has been inserted into a region.
+    fn notify_block_inserted(&mut self, block: MlirBlock);
+
+    /// Called when an existing operation was mutated in place rather than
+    /// replaced, e.g. one of its operands was changed with
+    /// `mlirOperationSetOperand`. The builder cannot detect such in-place
+    /// mutations on its own, so patterns that perform them must report them
+    /// explicitly through [`Builder::notify_operation_modified`].
+    fn notify_operation_modified(&mut self, op: MlirOperation);
+}
 
 /// A builder for MLIR operations.
 pub struct Builder {
@@ -17,6 +40,9 @@ pub struct Builder {
     /// The last block that was inserted. Used to order created blocks in
     /// sequence if there are no intermittent `set_insertion_point_*` calls.
     insert_block_after: Option<MlirBlock>,
+    /// An optional listener notified of every operation and block inserted
+    /// through this builder.
+    listener: Option<Box<dyn BuilderListener>>,
 }
 
 impl Builder {
@@ -28,6 +54,30 @@ impl Builder {
             insert_block: None,
             insert_point: InsertPoint::BlockStart,
             insert_block_after: None,
+            listener: None,
+        }
+    }
+
+    /// Register a listener to be notified of IR mutations performed through
+    /// this builder.
+    pub fn set_listener(&mut self, listener: Box<dyn BuilderListener>) {
+        self.listener = Some(listener);
+    }
+
+    /// Remove the currently registered listener, if any.
+    pub fn clear_listener(&mut self) {
+        self.listener = None;
+    }
+
+    /// Report to the active listener that `op` was mutated in place.
+    ///
+    /// Call this after mutating an operation directly (e.g. via
+    /// `mlirOperationSetOperand`) rather than through `insert`/`clone_op`,
+    /// so a worklist-driven rewriter revisits `op` for further
+    /// legalization.
+    pub fn notify_operation_modified(&mut self, op: MlirOperation) {
+        if let Some(listener) = &mut self.listener {
+            listener.notify_operation_modified(op);
         }
     }
 
@@ -57,8 +107,13 @@ impl Builder {
 
     /// Set the insertion point to before an operation.
     pub fn set_insertion_point_before(&mut self, op: impl OperationExt) {
-        self.insert_block = Some(op.parent_block());
-        self.insert_point = InsertPoint::Before(op.raw());
+        self.set_insertion_point_before_raw(op.raw());
+    }
+
+    /// Set the insertion point to before a raw operation.
+    fn set_insertion_point_before_raw(&mut self, op: MlirOperation) {
+        self.insert_block = Some(unsafe { mlirOperationGetBlock(op) });
+        self.insert_point = InsertPoint::Before(op);
         self.insert_block_after = self.insert_block;
     }
 
@@ -71,10 +126,14 @@ impl Builder {
 
     /// Insert an operation at the currently configured position.
     pub fn insert(&mut self, op: impl WrapRaw<RawType = MlirOperation>) {
+        self.insert_raw(op.raw());
+    }
+
+    /// Insert a raw operation at the currently configured position.
+    fn insert_raw(&mut self, op: MlirOperation) {
         let null_op = MlirOperation {
             ptr: std::ptr::null_mut(),
         };
-        let op = op.raw();
         let block = self.insert_block.expect("insertion block not set");
         unsafe {
             match self.insert_point {
@@ -87,6 +146,9 @@ impl Builder {
             }
         }
         self.insert_point = InsertPoint::After(op);
+        if let Some(listener) = &mut self.listener {
+            listener.notify_operation_inserted(op);
+        }
     }
 
     /// Build an operation through a callback that populates an
@@ -104,20 +166,497 @@ impl Builder {
 
     /// Create a new block after the current one.
     pub fn add_block(&mut self) -> MlirBlock {
+        self.add_block_with_args(&[])
+    }
+
+    /// Create a new block after the current one, with the given typed and
+    /// located arguments.
+    ///
+    /// A location is required per argument (rather than accepted as a
+    /// single default), matching MLIR's decision to make block-argument
+    /// locations mandatory.
+    pub fn add_block_with_args(&mut self, args: &[(Type, Location)]) -> MlirBlock {
         let block = self.insert_block.expect("insertion block not set");
         let after = self.insert_block_after.expect("insertion block not set");
+        let types: Vec<_> = args.iter().map(|(ty, _)| ty.raw()).collect();
+        let locs: Vec<_> = args.iter().map(|(_, loc)| loc.raw()).collect();
         unsafe {
-            let new_block = mlirBlockCreate(0, [].as_ptr());
+            let new_block = mlirBlockCreate(types.len() as isize, types.as_ptr(), locs.as_ptr());
             mlirRegionInsertOwnedBlockAfter(mlirBlockGetParentRegion(block), after, new_block);
             self.insert_block_after = Some(new_block);
+            if let Some(listener) = &mut self.listener {
+                listener.notify_block_inserted(new_block);
+            }
             new_block
         }
     }
+
+    /// Snapshot the current insertion point, returning a guard that restores
+    /// it when dropped.
+    ///
+    /// Lets callers temporarily reposition the builder and automatically pop
+    /// back to the prior position. The returned guard derefs to `Builder`,
+    /// so reposition and insert through it (not through the original
+    /// `builder` variable, which stays mutably borrowed for as long as the
+    /// guard is alive): `{ let mut g = builder.save_insertion_point();
+    /// g.set_insertion_point_before(x); g.insert(op); }`.
+    pub fn save_insertion_point(&mut self) -> InsertionGuard<'_> {
+        InsertionGuard {
+            insert_block: self.insert_block,
+            insert_point: self.insert_point,
+            insert_block_after: self.insert_block_after,
+            builder: self,
+        }
+    }
+
+    /// Detach `block` from whatever region currently owns it and append it
+    /// to the region of the current insertion point.
+    ///
+    /// Unlike [`Builder::add_block`], this moves an existing block (and
+    /// everything inside it) rather than creating a new empty one, e.g. to
+    /// hoist a block split off from another region.
+    pub fn move_block_into(&mut self, block: MlirBlock) {
+        let dest_region = unsafe {
+            mlirBlockGetParentRegion(self.insert_block.expect("insertion block not set"))
+        };
+        unsafe {
+            // Only detach if `block` currently has an owning region; it may
+            // already be unparented (e.g. freshly built but not yet
+            // inserted anywhere), in which case there is nothing to detach.
+            let owning_region = mlirBlockGetParentRegion(block);
+            if !owning_region.ptr.is_null() {
+                mlirBlockDetach(block);
+            }
+            mlirRegionAppendOwnedBlock(dest_region, block);
+        }
+        if let Some(listener) = &mut self.listener {
+            listener.notify_block_inserted(block);
+        }
+    }
+
+    /// Deep-clone an operation, together with any regions it contains, and
+    /// insert the copy at the current insertion point.
+    pub fn clone_op(&mut self, op: impl OperationExt) -> MlirOperation {
+        let cloned = unsafe { mlirOperationClone(op.raw()) };
+        self.insert_raw(cloned);
+        fixup_cloned_op(cloned, None, &mut self.listener);
+        cloned
+    }
+
+    /// Clone every block of `region` into the region of the current
+    /// insertion point, ahead of `before_block`.
+    ///
+    /// Block arguments and references to values defined inside `region` are
+    /// remapped to their cloned counterparts (including references from
+    /// operations nested inside a cloned op's own regions); references to
+    /// values defined outside `region` are left untouched. Mirrors MLIR's
+    /// decision to host `cloneRegionBefore` on the builder so that insertion
+    /// notifications are sent for every cloned block and operation, however
+    /// deeply nested. Successor blocks of cloned operations are not
+    /// remapped: cloned terminators still branch to the blocks of `region`.
+    pub fn clone_region_before(&mut self, region: MlirRegion, before_block: MlirBlock) {
+        let dest_region = unsafe {
+            mlirBlockGetParentRegion(self.insert_block.expect("insertion block not set"))
+        };
+        // The loop below repositions the insertion point to the end of each
+        // cloned block in turn; the guard restores the caller's original
+        // insertion point once it goes out of scope at the end of this
+        // function, rather than leaving it at the last cloned block.
+        let mut builder = self.save_insertion_point();
+
+        let mut value_map: HashMap<*const std::ffi::c_void, MlirValue> = HashMap::new();
+        let mut block_map: Vec<(MlirBlock, MlirBlock)> = Vec::new();
+
+        // First pass: create the cloned blocks (with copied argument
+        // types/locations) so that every block argument has a counterpart
+        // before any operation is cloned.
+        let mut block = unsafe { mlirRegionGetFirstBlock(region) };
+        while !block.ptr.is_null() {
+            let num_args = unsafe { mlirBlockGetNumArguments(block) };
+            let mut types = Vec::with_capacity(num_args as usize);
+            let mut locs = Vec::with_capacity(num_args as usize);
+            for i in 0..num_args {
+                let arg = unsafe { mlirBlockGetArgument(block, i) };
+                types.push(unsafe { mlirValueGetType(arg) });
+                locs.push(unsafe { mlirValueGetLocation(arg) });
+            }
+            let new_block =
+                unsafe { mlirBlockCreate(types.len() as isize, types.as_ptr(), locs.as_ptr()) };
+            unsafe {
+                mlirRegionInsertOwnedBlockBefore(dest_region, before_block, new_block);
+            }
+            if let Some(listener) = &mut builder.listener {
+                listener.notify_block_inserted(new_block);
+            }
+            for i in 0..num_args {
+                let old_arg = unsafe { mlirBlockGetArgument(block, i) };
+                let new_arg = unsafe { mlirBlockGetArgument(new_block, i) };
+                value_map.insert(old_arg.ptr, new_arg);
+            }
+            block_map.push((block, new_block));
+            block = unsafe { mlirBlockGetNextInRegion(block) };
+        }
+
+        // Second pass: clone the operations of each block into its clone,
+        // recording the cloned counterpart of every result.
+        let mut cloned_ops = Vec::new();
+        for (old_block, new_block) in block_map {
+            builder.set_insertion_point_to_end(new_block);
+            let mut op = unsafe { mlirBlockGetFirstOperation(old_block) };
+            while !op.ptr.is_null() {
+                let cloned = unsafe { mlirOperationClone(op) };
+                builder.insert_raw(cloned);
+                cloned_ops.push(cloned);
+                let num_results = unsafe { mlirOperationGetNumResults(op) };
+                for i in 0..num_results {
+                    let old_result = unsafe { mlirOperationGetResult(op, i) };
+                    let new_result = unsafe { mlirOperationGetResult(cloned, i) };
+                    value_map.insert(old_result.ptr, new_result);
+                }
+                op = unsafe { mlirOperationGetNextInBlock(op) };
+            }
+        }
+
+        // Third pass: with every value of `region` mapped, remap the operands
+        // of the cloned operations, including uses of values defined in
+        // blocks that come later in the region.
+        for cloned in cloned_ops {
+            fixup_cloned_op(cloned, Some(&value_map), &mut builder.listener);
+        }
+    }
 }
 
+/// Recursively fix up an operation freshly produced by [`Builder::clone_op`]
+/// or [`Builder::clone_region_before`]: remap its own operands (and,
+/// recursively, the operands of every operation nested inside its regions)
+/// against `value_map`, and notify `listener` of every block/operation found
+/// nested inside `op`'s regions.
+///
+/// `op` itself is assumed to already have been inserted and notified by the
+/// caller; only the IR nested inside it is handled here. `value_map` is
+/// `None` for [`Builder::clone_op`], which does not remap cross-references,
+/// and `Some` for [`Builder::clone_region_before`], which does.
+fn fixup_cloned_op(
+    op: MlirOperation,
+    value_map: Option<&HashMap<*const std::ffi::c_void, MlirValue>>,
+    listener: &mut Option<Box<dyn BuilderListener>>,
+) {
+    if let Some(value_map) = value_map {
+        let num_operands = unsafe { mlirOperationGetNumOperands(op) };
+        for i in 0..num_operands {
+            let operand = unsafe { mlirOperationGetOperand(op, i) };
+            if let Some(&new_operand) = value_map.get(&operand.ptr) {
+                unsafe { mlirOperationSetOperand(op, i, new_operand) };
+            }
+        }
+    }
+    let num_regions = unsafe { mlirOperationGetNumRegions(op) };
+    for i in 0..num_regions {
+        let region = unsafe { mlirOperationGetRegion(op, i) };
+        let mut block = unsafe { mlirRegionGetFirstBlock(region) };
+        while !block.ptr.is_null() {
+            if let Some(listener) = listener {
+                listener.notify_block_inserted(block);
+            }
+            let mut nested = unsafe { mlirBlockGetFirstOperation(block) };
+            while !nested.ptr.is_null() {
+                if let Some(listener) = listener {
+                    listener.notify_operation_inserted(nested);
+                }
+                fixup_cloned_op(nested, value_map, listener);
+                nested = unsafe { mlirOperationGetNextInBlock(nested) };
+            }
+            block = unsafe { mlirBlockGetNextInRegion(block) };
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
 enum InsertPoint {
     BlockStart,
     BlockEnd,
     After(MlirOperation),
     Before(MlirOperation),
 }
+
+/// An RAII guard that restores a [`Builder`]'s insertion point when dropped.
+///
+/// Obtained from [`Builder::save_insertion_point`]. Derefs to `Builder`, so
+/// callers reposition and insert through the guard itself rather than
+/// through the original `&mut Builder` (which stays borrowed for as long as
+/// the guard is alive) — the same pattern as `std::sync::MutexGuard`.
+pub struct InsertionGuard<'a> {
+    builder: &'a mut Builder,
+    insert_block: Option<MlirBlock>,
+    insert_point: InsertPoint,
+    insert_block_after: Option<MlirBlock>,
+}
+
+impl std::ops::Deref for InsertionGuard<'_> {
+    type Target = Builder;
+
+    fn deref(&self) -> &Builder {
+        self.builder
+    }
+}
+
+impl std::ops::DerefMut for InsertionGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Builder {
+        self.builder
+    }
+}
+
+impl Drop for InsertionGuard<'_> {
+    fn drop(&mut self) {
+        self.builder.insert_block = self.insert_block;
+        self.builder.insert_point = self.insert_point;
+        self.builder.insert_block_after = self.insert_block_after;
+    }
+}
+
+/// Extension methods for accessing a block's arguments.
+pub trait BlockExt {
+    /// The number of arguments the block takes.
+    fn num_arguments(&self) -> usize;
+
+    /// The value of the `index`-th argument.
+    fn argument(&self, index: usize) -> Value;
+}
+
+impl BlockExt for MlirBlock {
+    fn num_arguments(&self) -> usize {
+        unsafe { mlirBlockGetNumArguments(*self) as usize }
+    }
+
+    fn argument(&self, index: usize) -> Value {
+        Value::from_raw(unsafe { mlirBlockGetArgument(*self, index as isize) })
+    }
+}
+
+/// A rewrite pattern applied by [`apply_patterns_greedily`].
+pub trait RewritePattern {
+    /// Try to match and rewrite `op`. Returns whether the pattern applied,
+    /// in which case `builder` may have been used to mutate the IR.
+    fn match_and_rewrite(&self, op: MlirOperation, builder: &mut Builder) -> bool;
+}
+
+/// Configuration for [`apply_patterns_greedily`].
+pub struct GreedyRewriteConfig {
+    /// The maximum number of rewrite steps to attempt before giving up on
+    /// convergence, guarding against non-terminating rewrites.
+    pub max_iterations: usize,
+}
+
+impl Default for GreedyRewriteConfig {
+    fn default() -> Self {
+        Self {
+            max_iterations: 10_000,
+        }
+    }
+}
+
+/// A worklist of operations pending (re-)legalization, de-duplicated by
+/// operation identity.
+struct Worklist {
+    queue: VecDeque<MlirOperation>,
+    queued: HashSet<*const std::ffi::c_void>,
+}
+
+impl Worklist {
+    fn new() -> Self {
+        Self {
+            queue: VecDeque::new(),
+            queued: HashSet::new(),
+        }
+    }
+
+    fn push(&mut self, op: MlirOperation) {
+        if self.queued.insert(op.ptr) {
+            self.queue.push_back(op);
+        }
+    }
+
+    fn pop(&mut self) -> Option<MlirOperation> {
+        let op = self.queue.pop_front()?;
+        self.queued.remove(&op.ptr);
+        Some(op)
+    }
+}
+
+/// Seed `worklist` with a post-order walk of `region`, so that nested
+/// operations are visited (and thus rewritten) before their parents.
+fn seed_worklist_post_order(region: MlirRegion, worklist: &mut Worklist) {
+    let mut block = unsafe { mlirRegionGetFirstBlock(region) };
+    while !block.ptr.is_null() {
+        let mut op = unsafe { mlirBlockGetFirstOperation(block) };
+        while !op.ptr.is_null() {
+            let num_regions = unsafe { mlirOperationGetNumRegions(op) };
+            for i in 0..num_regions {
+                seed_worklist_post_order(unsafe { mlirOperationGetRegion(op, i) }, worklist);
+            }
+            worklist.push(op);
+            op = unsafe { mlirOperationGetNextInBlock(op) };
+        }
+        block = unsafe { mlirBlockGetNextInRegion(block) };
+    }
+}
+
+/// A [`BuilderListener`] that pushes every inserted operation onto a shared
+/// worklist, so that IR created or modified by a rewrite pattern is
+/// revisited for further legalization.
+struct WorklistListener(Rc<RefCell<Worklist>>);
+
+impl WorklistListener {
+    /// Push `op` onto the worklist, along with every operation that uses
+    /// one of its results, so that ops downstream of a new or modified
+    /// definition are revisited too.
+    fn enqueue_with_users(&mut self, op: MlirOperation) {
+        let mut worklist = self.0.borrow_mut();
+        worklist.push(op);
+        let num_results = unsafe { mlirOperationGetNumResults(op) };
+        for i in 0..num_results {
+            let result = unsafe { mlirOperationGetResult(op, i) };
+            let mut use_ = unsafe { mlirValueGetFirstUse(result) };
+            while !use_.ptr.is_null() {
+                worklist.push(unsafe { mlirOpOperandGetOwner(use_) });
+                use_ = unsafe { mlirOpOperandGetNextUse(use_) };
+            }
+        }
+    }
+}
+
+impl BuilderListener for WorklistListener {
+    fn notify_operation_inserted(&mut self, op: MlirOperation) {
+        self.enqueue_with_users(op);
+    }
+
+    fn notify_block_inserted(&mut self, _block: MlirBlock) {}
+
+    fn notify_operation_modified(&mut self, op: MlirOperation) {
+        self.enqueue_with_users(op);
+    }
+}
+
+/// Repeatedly apply `patterns` over `region` until fixpoint, driven by an
+/// explicit worklist seeded from a post-order walk of the region.
+///
+/// Mirrors a simple interpreter-style step loop: pop an operation off the
+/// worklist, try each pattern in turn, and rely on the builder's insertion
+/// notifications to push any newly created or modified operations (and
+/// their cloned/rewritten users) back onto the worklist. Returns whether the
+/// IR converged before `config.max_iterations` rewrite steps were spent.
+pub fn apply_patterns_greedily(
+    cx: Context,
+    region: MlirRegion,
+    patterns: &[Box<dyn RewritePattern>],
+    config: GreedyRewriteConfig,
+) -> bool {
+    let worklist = Rc::new(RefCell::new(Worklist::new()));
+    seed_worklist_post_order(region, &mut worklist.borrow_mut());
+
+    let mut builder = Builder::new(cx);
+    builder.set_listener(Box::new(WorklistListener(worklist.clone())));
+
+    let mut iterations = 0;
+    let converged = loop {
+        let op = match worklist.borrow_mut().pop() {
+            Some(op) => op,
+            None => break true,
+        };
+        if iterations >= config.max_iterations {
+            break false;
+        }
+        iterations += 1;
+
+        builder.set_insertion_point_before_raw(op);
+        for pattern in patterns {
+            if pattern.match_and_rewrite(op, &mut builder) {
+                break;
+            }
+        }
+    };
+
+    builder.clear_listener();
+    converged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A non-null `MlirOperation` distinguishable from others by `addr`,
+    /// for exercising `Worklist`'s pointer-identity dedup without a live
+    /// MLIR context.
+    fn fake_op(addr: usize) -> MlirOperation {
+        MlirOperation {
+            ptr: addr as *mut _,
+        }
+    }
+
+    /// A non-null `MlirBlock` distinguishable from others by `addr`. Only
+    /// suitable for tests that compare raw pointer identity directly
+    /// (`.ptr`) rather than through `MlirBlock`'s `PartialEq`, which calls
+    /// into `mlirBlockEqual` and requires a real block.
+    fn fake_block(addr: usize) -> MlirBlock {
+        MlirBlock {
+            ptr: addr as *mut _,
+        }
+    }
+
+    #[test]
+    fn insertion_guard_restores_previous_insertion_point_on_drop() {
+        let cx = Context::new();
+        let mut builder = Builder::new(cx);
+        let outer = fake_block(1);
+        let inner = fake_block(2);
+
+        builder.set_insertion_point_to_start(outer);
+        {
+            // Reposition through the guard, exactly as
+            // documented on `Builder::save_insertion_point` — this is the
+            // usage that must compile without fighting the borrow checker.
+            let mut guard = builder.save_insertion_point();
+            guard.set_insertion_point_to_end(inner);
+            assert_eq!(guard.insert_block.map(|b| b.ptr), Some(inner.ptr));
+        }
+        assert_eq!(builder.insert_block.map(|b| b.ptr), Some(outer.ptr));
+    }
+
+    #[test]
+    fn worklist_pops_in_fifo_order() {
+        let mut worklist = Worklist::new();
+        let a = fake_op(1);
+        let b = fake_op(2);
+        worklist.push(a);
+        worklist.push(b);
+        assert_eq!(worklist.pop().unwrap().ptr, a.ptr);
+        assert_eq!(worklist.pop().unwrap().ptr, b.ptr);
+        assert!(worklist.pop().is_none());
+    }
+
+    #[test]
+    fn worklist_dedups_pending_pushes_by_identity() {
+        let mut worklist = Worklist::new();
+        let a = fake_op(1);
+        worklist.push(a);
+        worklist.push(a);
+        worklist.push(a);
+        assert_eq!(worklist.pop().unwrap().ptr, a.ptr);
+        assert!(worklist.pop().is_none());
+    }
+
+    #[test]
+    fn worklist_allows_requeue_after_it_was_popped() {
+        let mut worklist = Worklist::new();
+        let a = fake_op(1);
+        worklist.push(a);
+        worklist.pop();
+        worklist.push(a);
+        assert!(worklist.pop().is_some());
+    }
+
+    #[test]
+    fn greedy_rewrite_config_default_allows_at_least_one_step() {
+        assert!(GreedyRewriteConfig::default().max_iterations > 0);
+    }
+}